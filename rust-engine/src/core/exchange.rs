@@ -0,0 +1,85 @@
+use crate::core::rates;
+use crate::core::worker::{transact_with_options, TransactError};
+use crate::models::transaction::Transaction;
+use chrono::{DateTime, Utc};
+use redis::aio::MultiplexedConnection;
+use rust_decimal::Decimal;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// Looks up `account_id`'s currency. A missing row is a business-rule
+/// rejection (the account will never exist just because we retry), but any
+/// other `sqlx::Error` — a dropped connection, a timeout — is transient and
+/// worth retrying, so it's kept as `TransactError::Sql` rather than
+/// flattened into the same message.
+async fn account_currency(pool: &PgPool, account_id: i32) -> Result<String, TransactError> {
+    sqlx::query_scalar::<_, String>("SELECT currency FROM accounts WHERE id = $1")
+        .bind(account_id)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => {
+                TransactError::Business(format!("Account {} not found", account_id))
+            }
+            e => TransactError::Sql(e),
+        })
+}
+
+/// Builds a transfer that may cross currencies: looks up both accounts'
+/// currencies, and — if they differ — converts `amount` (in the source
+/// account's currency) into the target's currency at the current rate
+/// before debiting/crediting. Same-currency transfers skip the rate lookup
+/// entirely and behave exactly like `Transaction::transfer`. Split out
+/// from `transfer` so a caller batching several transfers together (see
+/// `worker::transact_batch`) can build each `Transaction` up front and run
+/// them all in one round trip, instead of one `transact` per transfer.
+#[allow(clippy::too_many_arguments)]
+pub async fn build_transfer(
+    pool: &PgPool,
+    redis_conn: &mut MultiplexedConnection,
+    id: Uuid,
+    created_at: DateTime<Utc>,
+    source: i32,
+    target: i32,
+    amount: Decimal,
+) -> Result<Transaction, TransactError> {
+    let source_currency = account_currency(pool, source).await?;
+    let target_currency = account_currency(pool, target).await?;
+
+    if source_currency == target_currency {
+        return Ok(Transaction::transfer(id, created_at, source, target, amount, source_currency));
+    }
+
+    let rate = rates::lookup_rate(redis_conn, &source_currency, &target_currency)
+        .await
+        .map_err(TransactError::Business)?;
+    let converted = rates::convert(amount, rate, rates::currency_scale(&target_currency));
+    Ok(Transaction::exchange(
+        id,
+        created_at,
+        source,
+        source_currency,
+        amount,
+        target,
+        target_currency,
+        converted,
+    ))
+}
+
+/// Builds and immediately runs a single transfer. Convenience wrapper
+/// around `build_transfer` + `transact_with_options` for callers that
+/// don't need batching.
+#[allow(clippy::too_many_arguments)]
+pub async fn transfer(
+    pool: &PgPool,
+    redis_conn: &mut MultiplexedConnection,
+    id: Uuid,
+    created_at: DateTime<Utc>,
+    source: i32,
+    target: i32,
+    amount: Decimal,
+    serializable: bool,
+) -> Result<(), TransactError> {
+    let transaction = build_transfer(pool, redis_conn, id, created_at, source, target, amount).await?;
+    transact_with_options(pool, transaction, serializable).await
+}
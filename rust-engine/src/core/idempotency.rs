@@ -0,0 +1,139 @@
+use crate::models::status::Status;
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use uuid::Uuid;
+
+/// How long a `Pending` claim is held before another delivery of the same
+/// idempotency key is allowed to reclaim it, in case the worker that made
+/// the original claim crashed mid-flight.
+const CLAIM_TTL_SECONDS: u64 = 30;
+
+/// Fixed namespace used to derive a transaction's `Uuid` from its
+/// idempotency key (arbitrary, but must never change). Picking a stable id
+/// this way — rather than `Uuid::new_v4()` per delivery — means every
+/// redelivery of the same key carries the same id, so its `transactions`
+/// row, its `transfer_status` row and its postings agree on one id across
+/// retries. (The claim itself in `worker::transact_batch` conflicts on
+/// `idempotency_key`, not on this id.)
+const TRANSACTION_ID_NAMESPACE: Uuid = Uuid::from_bytes([
+    0x6b, 0xa7, 0xb8, 0x14, 0x9d, 0xad, 0x11, 0xd1, 0x80, 0xb4, 0x00, 0xc0, 0x4f, 0xd4, 0x30, 0xc8,
+]);
+
+/// Derives the `Uuid` a transfer with this idempotency key should use as
+/// its `Transaction::id()`, so retries of the same key always agree on it.
+pub fn derive_transaction_id(idempotency_key: &str) -> Uuid {
+    Uuid::new_v5(&TRANSACTION_ID_NAMESPACE, idempotency_key.as_bytes())
+}
+
+/// Outcome of attempting to claim an idempotency key before running a
+/// transfer.
+pub enum Claim {
+    /// Nobody else is working on this key; go ahead and run the transfer,
+    /// then report the result with `mark_confirmed`/`mark_failed`/
+    /// `mark_delayed`.
+    Acquired,
+    /// This key already reached a terminal status; here is the status (and
+    /// any detail) recorded last time, so the transfer should not be
+    /// re-applied.
+    AlreadyCompleted(String),
+    /// Another delivery of this key is still `Pending` and its claim
+    /// hasn't expired yet; the caller should requeue and try again later.
+    InProgress,
+}
+
+fn redis_key(idempotency_key: &str) -> String {
+    format!("status:{}", idempotency_key)
+}
+
+fn encode(status: Status, detail: Option<&str>) -> String {
+    match detail {
+        Some(detail) => format!("{}: {}", status, detail),
+        None => status.to_string(),
+    }
+}
+
+/// Atomically claims `idempotency_key` for processing using `SET key
+/// "pending" NX EX <ttl>`. The `NX` makes the claim atomic across
+/// concurrent deliveries, and the TTL means a worker crashing between
+/// claiming the key and recording a terminal status doesn't wedge it
+/// forever.
+pub async fn claim(
+    redis_conn: &mut MultiplexedConnection,
+    idempotency_key: &str,
+) -> Result<Claim, redis::RedisError> {
+    let key = redis_key(idempotency_key);
+
+    // Retried once: if our SET NX loses a race against a claim that expires
+    // right after, the following GET can observe nothing at all, in which
+    // case the key is free again and we just try the claim once more.
+    for _ in 0..2 {
+        let claimed: Option<String> = redis::cmd("SET")
+            .arg(&key)
+            .arg(Status::Pending.as_str())
+            .arg("NX")
+            .arg("EX")
+            .arg(CLAIM_TTL_SECONDS)
+            .query_async(redis_conn)
+            .await?;
+
+        if claimed.is_some() {
+            return Ok(Claim::Acquired);
+        }
+
+        let existing: Option<String> = redis_conn.get(&key).await?;
+        match existing {
+            Some(value) if value == Status::Pending.as_str() => return Ok(Claim::InProgress),
+            // A `Delayed` job isn't being worked on by anyone right now —
+            // it was republished onto the retry queue to wait out a backoff — so
+            // a redelivery is free to reclaim it directly rather than
+            // racing a `SET NX` that would only ever lose to the stale
+            // value already sitting in the key.
+            Some(value) if value.starts_with(Status::Delayed.as_str()) => {
+                redis_conn
+                    .set_ex::<_, _, ()>(&key, Status::Pending.as_str(), CLAIM_TTL_SECONDS)
+                    .await?;
+                return Ok(Claim::Acquired);
+            }
+            Some(value) => return Ok(Claim::AlreadyCompleted(value)),
+            None => continue,
+        }
+    }
+
+    Ok(Claim::Acquired)
+}
+
+/// Marks `idempotency_key` `Confirmed`, the terminal success status.
+pub async fn mark_confirmed(
+    redis_conn: &mut MultiplexedConnection,
+    idempotency_key: &str,
+) -> Result<(), redis::RedisError> {
+    redis_conn
+        .set(redis_key(idempotency_key), encode(Status::Confirmed, None))
+        .await
+}
+
+/// Marks `idempotency_key` `Failed` with `detail`, a terminal status: a
+/// business-rule rejection, or a transient failure that ran out of
+/// delivery attempts.
+pub async fn mark_failed(
+    redis_conn: &mut MultiplexedConnection,
+    idempotency_key: &str,
+    detail: &str,
+) -> Result<(), redis::RedisError> {
+    redis_conn
+        .set(redis_key(idempotency_key), encode(Status::Failed, Some(detail)))
+        .await
+}
+
+/// Marks `idempotency_key` `Delayed` with `detail`: a transient failure
+/// that will be retried, not terminal. Left without a TTL — `claim` treats
+/// a `Delayed` value as immediately reclaimable by the next redelivery.
+pub async fn mark_delayed(
+    redis_conn: &mut MultiplexedConnection,
+    idempotency_key: &str,
+    detail: &str,
+) -> Result<(), redis::RedisError> {
+    redis_conn
+        .set(redis_key(idempotency_key), encode(Status::Delayed, Some(detail)))
+        .await
+}
@@ -0,0 +1,6 @@
+pub mod exchange;
+pub mod idempotency;
+pub mod rates;
+pub mod retry;
+pub mod status;
+pub mod worker;
@@ -0,0 +1,109 @@
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use rust_decimal::{Decimal, RoundingStrategy};
+use std::str::FromStr;
+
+/// Redis hash holding the current FX rates, refreshed out of band by
+/// whatever feeds the worker: `rates:current` maps a `"FROM:TO"` field to a
+/// `Decimal` string that converts one unit of `FROM` into `TO`.
+const RATES_HASH_KEY: &str = "rates:current";
+
+/// Placeholder rate feed: a fixed table of `(FROM, TO, rate)` triples,
+/// refreshed into `rates:current` on a timer by `refresh`. Swap this out for
+/// a real upstream feed (a pricing API, a central-bank publication) once one
+/// is wired up; until then this keeps the hash populated so cross-currency
+/// transfers don't fail outright.
+const SEED_RATES: &[(&str, &str, &str)] = &[
+    ("USD", "EUR", "0.92"),
+    ("EUR", "USD", "1.087"),
+    ("USD", "GBP", "0.79"),
+    ("GBP", "USD", "1.266"),
+    ("USD", "JPY", "157.3"),
+    ("JPY", "USD", "0.00636"),
+];
+
+fn pair_field(from: &str, to: &str) -> String {
+    format!("{}:{}", from, to)
+}
+
+/// Re-seeds `rates:current` from `SEED_RATES`. Called on a timer from
+/// `main` so the hash never goes stale for longer than the refresh
+/// interval; every call is a full overwrite, so a rate dropped from
+/// `SEED_RATES` stops being offered on the next refresh rather than
+/// lingering forever.
+pub async fn refresh(redis_conn: &mut MultiplexedConnection) -> Result<(), String> {
+    let fields: Vec<(String, &str)> = SEED_RATES
+        .iter()
+        .map(|(from, to, rate)| (pair_field(from, to), *rate))
+        .collect();
+
+    let refreshed: redis::RedisResult<()> = redis::pipe()
+        .atomic()
+        .del(RATES_HASH_KEY)
+        .ignore()
+        .hset_multiple(RATES_HASH_KEY, &fields)
+        .ignore()
+        .query_async(redis_conn)
+        .await;
+    refreshed.map_err(|e| format!("Failed to refresh exchange rates: {}", e))
+}
+
+/// Looks up the current rate to convert `from` into `to`. Identical
+/// currencies convert at `1` without a Redis round trip.
+pub async fn lookup_rate(
+    redis_conn: &mut MultiplexedConnection,
+    from: &str,
+    to: &str,
+) -> Result<Decimal, String> {
+    if from == to {
+        return Ok(Decimal::ONE);
+    }
+
+    let raw: Option<String> = redis_conn
+        .hget(RATES_HASH_KEY, pair_field(from, to))
+        .await
+        .map_err(|e| format!("Failed to look up exchange rate {}->{}: {}", from, to, e))?;
+
+    let raw = raw.ok_or_else(|| format!("No exchange rate available for {}->{}", from, to))?;
+
+    Decimal::from_str(&raw).map_err(|e| format!("Malformed exchange rate {}->{}: {}", from, to, e))
+}
+
+/// Converts `amount` at `rate`, rounding to `to_scale` decimal places using
+/// banker's rounding (round-half-to-even), the convention most settlement
+/// systems use so repeated conversions don't systematically favour either
+/// side of the trade.
+pub fn convert(amount: Decimal, rate: Decimal, to_scale: u32) -> Decimal {
+    (amount * rate).round_dp_with_strategy(to_scale, RoundingStrategy::MidpointNearestEven)
+}
+
+/// Minor-unit scale (decimal places) for a currency's smallest unit. Falls
+/// back to 2 (cents) for any code not explicitly listed here.
+pub fn currency_scale(currency: &str) -> u32 {
+    match currency {
+        "JPY" | "KRW" | "VND" => 0,
+        "BHD" | "KWD" | "OMR" => 3,
+        _ => 2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_convert_rounds_half_to_even() {
+        // 2.345 at scale 2 is exactly on the boundary; banker's rounding
+        // takes it to the nearest even digit (2.34), not always up.
+        let converted = convert(dec!(1), dec!(2.345), 2);
+        assert_eq!(converted, dec!(2.34));
+    }
+
+    #[test]
+    fn test_currency_scale_defaults_to_two() {
+        assert_eq!(currency_scale("USD"), 2);
+        assert_eq!(currency_scale("JPY"), 0);
+        assert_eq!(currency_scale("BHD"), 3);
+    }
+}
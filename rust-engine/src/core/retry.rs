@@ -0,0 +1,55 @@
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use std::time::Duration;
+
+/// Base for the exponential backoff applied between queue-level redelivery
+/// attempts, in seconds. The 1st retry waits ~`BACKOFF_BASE_SECONDS`, the
+/// 2nd ~2x that, and so on.
+const BACKOFF_BASE_SECONDS: u64 = 2;
+
+/// How long a delivery-attempt counter is kept in Redis. Generous relative
+/// to any realistic run of retries, but still bounded so a key nobody ever
+/// finishes with doesn't linger forever.
+const ATTEMPT_COUNTER_TTL_SECONDS: i64 = 3600;
+
+fn attempts_key(idempotency_key: &str) -> String {
+    format!("attempts:{}", idempotency_key)
+}
+
+/// Increments and returns the number of delivery attempts recorded so far
+/// for `idempotency_key`, creating the counter (with a TTL) the first time
+/// it's called for that key. Counts queue-level redeliveries after a
+/// transient failure, not the in-process `SERIALIZABLE` retries `transact`
+/// already handles on its own.
+pub async fn record_attempt(
+    redis_conn: &mut MultiplexedConnection,
+    idempotency_key: &str,
+) -> Result<u32, redis::RedisError> {
+    let key = attempts_key(idempotency_key);
+    let attempts: u32 = redis_conn.incr(&key, 1).await?;
+    if attempts == 1 {
+        redis_conn
+            .expire::<_, ()>(&key, ATTEMPT_COUNTER_TTL_SECONDS)
+            .await?;
+    }
+    Ok(attempts)
+}
+
+/// How long to wait before redelivering a job for the `attempt`th time
+/// (1-indexed), so repeated transient failures back off instead of
+/// hammering a dependency that's already struggling.
+pub fn backoff(attempt: u32) -> Duration {
+    Duration::from_secs(BACKOFF_BASE_SECONDS * 2u64.pow(attempt.saturating_sub(1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_each_attempt() {
+        assert_eq!(backoff(1), Duration::from_secs(2));
+        assert_eq!(backoff(2), Duration::from_secs(4));
+        assert_eq!(backoff(3), Duration::from_secs(8));
+    }
+}
@@ -0,0 +1,65 @@
+use crate::models::status::Status;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// Upserts the current lifecycle `status` of a job into the
+/// `transfer_status` table, keyed by `idempotency_key`, so a poller can see
+/// progress (and why a job is stuck) without waiting on the queue. `detail`
+/// carries the business-rule rejection or transient error message for
+/// `Failed` and `Delayed` rows; it's `None` for `Proposed`/`Pending`/
+/// `Confirmed`.
+///
+/// Deliberately a different table from the one `core::worker::transact_batch`
+/// uses to claim idempotency keys: this one is allowed to carry a
+/// committed non-terminal status like `Delayed` (that's the whole point —
+/// it's for polling progress), but the claim table must never see one, or
+/// a redelivery reading it back could mistake an in-progress row for a
+/// finished one and skip re-applying the transfer.
+pub async fn record(
+    pool: &PgPool,
+    transaction_id: Uuid,
+    idempotency_key: &str,
+    status: Status,
+    detail: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO transfer_status (id, idempotency_key, status, detail, updated_at)
+         VALUES ($1, $2, $3, $4, now())
+         ON CONFLICT (idempotency_key)
+         DO UPDATE SET id = EXCLUDED.id,
+                       status = EXCLUDED.status,
+                       detail = EXCLUDED.detail,
+                       updated_at = EXCLUDED.updated_at",
+    )
+    .bind(transaction_id)
+    .bind(idempotency_key)
+    .bind(status.as_str())
+    .bind(detail)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test]
+    async fn test_record_upserts_status_and_detail(pool: PgPool) {
+        let id = Uuid::new_v4();
+
+        record(&pool, id, "key-1", Status::Pending, None).await.unwrap();
+        record(&pool, id, "key-1", Status::Failed, Some("insufficient funds")).await.unwrap();
+
+        let (status, detail): (String, Option<String>) =
+            sqlx::query_as("SELECT status, detail FROM transfer_status WHERE idempotency_key = $1")
+                .bind("key-1")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+
+        assert_eq!(status, "failed");
+        assert_eq!(detail.as_deref(), Some("insufficient funds"));
+    }
+}
@@ -1,95 +1,579 @@
-use crate::models::transaction::Transaction;
+use crate::models::status::Status;
+use crate::models::transaction::{AccountId, Transaction};
 use rust_decimal::Decimal;
-use sqlx::{PgPool, Postgres}; 
-use tokio::join;
+use sqlx::{PgPool, Postgres};
+use std::collections::HashMap;
+use std::time::Duration;
+use uuid::Uuid;
 
-async fn get_account_balance(pool: &PgPool, account_id: i32) -> Result<Decimal, sqlx::Error> {
-    let balance: Decimal = sqlx::query_scalar::<_, Decimal>("SELECT balance FROM accounts WHERE id = $1")
-        .bind(account_id)
-        .fetch_one(pool)
-        .await?;
-    Ok(balance)
+/// SQLSTATE Postgres reports for a serialization failure under
+/// `SERIALIZABLE`. A deadlock victim gets `40P01` instead, which this does
+/// not match.
+const SERIALIZATION_FAILURE_SQLSTATE: &str = "40001";
+
+/// How many times `transact_with_options` and `transact_batch` retry the
+/// whole closure after a serialization failure before giving up.
+const MAX_SERIALIZATION_RETRIES: u32 = 5;
+
+/// Errors produced while actually moving money, as opposed to the business
+/// rule rejections `verify` hands back. Kept separate so `transact` can tell
+/// "retry me" (a transient `sqlx::Error`) apart from "never retry me" (an
+/// insufficient-funds style rule violation). `pub(crate)` so the queue
+/// consumer in `main.rs` can tell the two apart too, to decide between a
+/// `Delayed` retry and going straight to `Failed`.
+pub(crate) enum TransactError {
+    Sql(sqlx::Error),
+    Business(String),
 }
 
-pub async fn verify(pool: &PgPool, transaction: &Transaction) -> Result<(), String> {
-    if transaction.value() <= Decimal::ZERO {
-        return Err("Transaction value must be positive".to_string());
-    }
-    if transaction.source() == transaction.target() {
-        return Err("Target and Source same".to_string());
+impl TransactError {
+    fn is_serialization_failure(&self) -> bool {
+        match self {
+            TransactError::Sql(e) => e
+                .as_database_error()
+                .and_then(|d| d.code())
+                .as_deref()
+                == Some(SERIALIZATION_FAILURE_SQLSTATE),
+            TransactError::Business(_) => false,
+        }
     }
 
-    let (source_result, target_result) = join!(
-        get_account_balance(pool, transaction.source()),
-        get_account_balance(pool, transaction.target())
-    );
+    /// True for failures that might succeed if retried later: a dropped
+    /// connection, a `SERIALIZABLE` conflict, a statement timeout. False
+    /// for business-rule rejections, which will fail identically forever.
+    pub(crate) fn is_transient(&self) -> bool {
+        matches!(self, TransactError::Sql(_))
+    }
+}
 
-    match (source_result, target_result) {
-        (Ok(source_value), Ok(_target_value)) => {
-            if source_value < transaction.value() {
-                Err("Source account has insufficient funds".to_string())
-            } else {
-                Ok(())
-            }
+impl std::fmt::Display for TransactError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransactError::Sql(e) => write!(f, "{}", e),
+            TransactError::Business(msg) => write!(f, "{}", msg),
         }
-        (Err(_), Ok(_)) => Err("Source account not found".to_string()),
-        (Ok(_), Err(_)) => Err("Target account not found".to_string()),
-        (Err(_), Err(_)) => Err("Both accounts not found".to_string()),
     }
 }
 
-// Accepts &mut Transaction for atomicity
-async fn push_transaction(tx: &mut sqlx::Transaction<'_, Postgres>, transaction: &Transaction) -> Result<(), sqlx::Error> {
-    sqlx::query("UPDATE accounts SET balance = balance - $1 WHERE id = $2")
-        .bind(transaction.value())
-        .bind(transaction.source())
-        .execute(&mut **tx) 
-        .await?;
+impl From<sqlx::Error> for TransactError {
+    fn from(e: sqlx::Error) -> Self {
+        TransactError::Sql(e)
+    }
+}
+
+/// Structural checks that don't need a database round trip: a transaction
+/// must move at least two legs, none of them a no-op, and — when every leg
+/// shares a currency — they must net to exactly zero. A currency-converting
+/// transaction's legs are denominated in different currencies and so can
+/// never net to zero as raw `Decimal`s; those are trusted to have been built
+/// correctly by `core::exchange`, which applies the conversion rate before
+/// constructing the transaction. The balance check used to live here too,
+/// but reading it outside the transaction that later debits the account let
+/// two concurrent transfers both see enough funds and overdraw it, so that
+/// check now happens under `FOR UPDATE` inside `transact`.
+pub fn verify(transaction: &Transaction) -> Result<(), String> {
+    if transaction.legs().len() < 2 {
+        return Err("Transaction must have at least two legs".to_string());
+    }
+    if transaction.legs().iter().any(|leg| leg.amount() == Decimal::ZERO) {
+        return Err("Transaction legs must be non-zero".to_string());
+    }
+    if transaction.is_single_currency() && !transaction.is_balanced() {
+        return Err("Transaction legs must net to zero".to_string());
+    }
+    Ok(())
+}
+
+fn distinct_account_ids(transaction: &Transaction) -> Vec<AccountId> {
+    let mut ids: Vec<AccountId> = transaction.legs().iter().map(|leg| leg.account_id()).collect();
+    ids.sort_unstable();
+    ids.dedup();
+    ids
+}
 
-    sqlx::query("UPDATE accounts SET balance = balance + $1 WHERE id = $2")
-        .bind(transaction.value())
-        .bind(transaction.target())
+async fn lock_account_balance(
+    tx: &mut sqlx::Transaction<'_, Postgres>,
+    account_id: AccountId,
+) -> Result<Decimal, sqlx::Error> {
+    sqlx::query_scalar::<_, Decimal>("SELECT balance FROM accounts WHERE id = $1 FOR UPDATE")
+        .bind(account_id)
+        .fetch_one(&mut **tx)
+        .await
+}
+
+/// Locks every account touched by the transaction in ascending
+/// `account_id` order (regardless of which leg it belongs to) so two
+/// transactions that touch the same accounts in opposite directions can
+/// never deadlock waiting on each other's `FOR UPDATE` locks.
+async fn lock_balances(
+    tx: &mut sqlx::Transaction<'_, Postgres>,
+    account_ids: &[AccountId],
+) -> Result<HashMap<AccountId, Decimal>, TransactError> {
+    let mut balances = HashMap::with_capacity(account_ids.len());
+    for &account_id in account_ids {
+        let balance = lock_account_balance(tx, account_id)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => {
+                    TransactError::Business(format!("Account {} not found", account_id))
+                }
+                e => TransactError::Sql(e),
+            })?;
+        balances.insert(account_id, balance);
+    }
+    Ok(balances)
+}
+
+/// Applies every leg of the transaction to `accounts.balance` and records it
+/// as an immutable row in `postings`, all inside the caller's transaction.
+async fn push_transaction(
+    tx: &mut sqlx::Transaction<'_, Postgres>,
+    transaction: &Transaction,
+) -> Result<(), sqlx::Error> {
+    for leg in transaction.legs() {
+        sqlx::query("UPDATE accounts SET balance = balance + $1 WHERE id = $2")
+            .bind(leg.amount())
+            .bind(leg.account_id())
+            .execute(&mut **tx)
+            .await?;
+
+        sqlx::query(
+            "INSERT INTO postings (transaction_id, account_id, amount, created_at) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(transaction.id())
+        .bind(leg.account_id())
+        .bind(leg.amount())
+        .bind(transaction.created_at())
         .execute(&mut **tx)
         .await?;
-        
+    }
+
+    Ok(())
+}
+
+fn distinct_account_ids_across<'a>(
+    transactions: impl IntoIterator<Item = &'a Transaction>,
+) -> Vec<AccountId> {
+    let mut ids: Vec<AccountId> = transactions
+        .into_iter()
+        .flat_map(|transaction| transaction.legs().iter().map(|leg| leg.account_id()))
+        .collect();
+    ids.sort_unstable();
+    ids.dedup();
+    ids
+}
+
+/// Same idea as `lock_balances`, but for every account touched by a whole
+/// batch at once: one `SELECT ... WHERE id = ANY($1) FOR UPDATE` instead of
+/// one round trip per account. An id with no matching row just isn't in
+/// the returned map; `apply_one` turns that into the same "Account N not
+/// found" rejection `lock_balances` would have given that transaction.
+///
+/// `ORDER BY id` matters here as much as the `FOR UPDATE` itself: without
+/// it Postgres is free to lock the matched rows in scan order rather than
+/// ascending `account_id`, which reintroduces exactly the cross-batch
+/// deadlock `lock_balances`'s sorted, one-at-a-time locking was written to
+/// avoid.
+async fn lock_balances_batch(
+    tx: &mut sqlx::Transaction<'_, Postgres>,
+    account_ids: &[AccountId],
+) -> Result<HashMap<AccountId, Decimal>, TransactError> {
+    let rows: Vec<(AccountId, Decimal)> = sqlx::query_as(
+        "SELECT id, balance FROM accounts WHERE id = ANY($1) ORDER BY id FOR UPDATE",
+    )
+    .bind(account_ids)
+    .fetch_all(&mut **tx)
+    .await?;
+
+    Ok(rows.into_iter().collect())
+}
+
+/// Verifies and applies a single transaction within a batch against the
+/// running `balances` (which `transact_batch` seeds from `ANY($1)` and this
+/// updates in place), so a later entry in the same batch sees the effect of
+/// an earlier one on a shared account.
+async fn apply_one(
+    tx: &mut sqlx::Transaction<'_, Postgres>,
+    transaction: &Transaction,
+    balances: &mut HashMap<AccountId, Decimal>,
+) -> Result<(), TransactError> {
+    verify(transaction).map_err(TransactError::Business)?;
+
+    let mut net_by_account: HashMap<AccountId, Decimal> = HashMap::new();
+    for leg in transaction.legs() {
+        *net_by_account.entry(leg.account_id()).or_insert(Decimal::ZERO) += leg.amount();
+    }
+
+    for (account_id, delta) in &net_by_account {
+        let balance = balances.get(account_id).copied().ok_or_else(|| {
+            TransactError::Business(format!("Account {} not found", account_id))
+        })?;
+        if balance + *delta < Decimal::ZERO {
+            return Err(TransactError::Business(format!(
+                "Account {} has insufficient funds",
+                account_id
+            )));
+        }
+    }
+
+    push_transaction(tx, transaction).await?;
+
+    for (account_id, delta) in net_by_account {
+        *balances.get_mut(&account_id).expect("checked above") += delta;
+    }
+
     Ok(())
 }
 
-pub async fn transact(pool: &PgPool, transaction: Transaction) -> Result<(), String> {
-    // 1. Verify (Read Phase)
-    verify(pool, &transaction).await?;
+/// Claims `idempotency_key` by writing its `transactions` row in the same
+/// Postgres transaction that's about to move money, instead of in a
+/// separate Redis round trip beforehand. That's what makes the claim
+/// crash-safe: the write and the balance updates below either commit
+/// together or roll back together, so a worker that dies between "money
+/// moved" and "result recorded" can never have a redelivery re-apply the
+/// transfer.
+///
+/// The `ON CONFLICT ... DO UPDATE ... WHERE transactions.status NOT IN
+/// (...)` clause reclaims the row rather than leaving it alone whenever
+/// the existing status isn't terminal — a fresh claim on a key nobody has
+/// ever finished looks exactly like a reclaim of one somebody started but
+/// never finished, and both cases must re-run `apply_one`, never silently
+/// report success. Only a row that's genuinely `confirmed` or `failed`
+/// fails the `WHERE` and comes back empty from `RETURNING`.
+///
+/// Returns `None` if the claim was acquired (go ahead and apply it), or
+/// `Some` outcome already recorded by an earlier attempt at this key.
+async fn claim_or_replay(
+    tx: &mut sqlx::Transaction<'_, Postgres>,
+    transaction_id: Uuid,
+    idempotency_key: &str,
+) -> Result<Option<Result<(), TransactError>>, TransactError> {
+    let claimed: Option<(Uuid,)> = sqlx::query_as(
+        "INSERT INTO transactions (id, idempotency_key, status, updated_at)
+         VALUES ($1, $2, $3, now())
+         ON CONFLICT (idempotency_key) DO UPDATE
+             SET id = EXCLUDED.id, status = EXCLUDED.status, updated_at = EXCLUDED.updated_at
+             WHERE transactions.status NOT IN ($4, $5)
+         RETURNING id",
+    )
+    .bind(transaction_id)
+    .bind(idempotency_key)
+    .bind(Status::Pending.as_str())
+    .bind(Status::Confirmed.as_str())
+    .bind(Status::Failed.as_str())
+    .fetch_optional(&mut **tx)
+    .await?;
+
+    if claimed.is_some() {
+        return Ok(None);
+    }
+
+    // The claim above only comes back empty when the existing row is
+    // already `confirmed` or `failed` — anything else was just reclaimed
+    // by the write, so this read can only ever observe a terminal status.
+    let (status, detail): (String, Option<String>) =
+        sqlx::query_as("SELECT status, detail FROM transactions WHERE idempotency_key = $1")
+            .bind(idempotency_key)
+            .fetch_one(&mut **tx)
+            .await?;
 
-    // 2. Start Atomic Transaction
-    let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+    Ok(Some(if status == Status::Confirmed.as_str() {
+        Ok(())
+    } else {
+        debug_assert_eq!(status, Status::Failed.as_str());
+        Err(TransactError::Business(detail.unwrap_or_default()))
+    }))
+}
 
-    // 3. Attempt Updates
-    push_transaction(&mut tx, &transaction).await.map_err(|e| e.to_string())?;
+/// Records the terminal outcome of a freshly claimed entry back onto its
+/// `transactions` row, in the same transaction, so a later replay of the
+/// same idempotency key (see `claim_or_replay`) sees it.
+async fn record_outcome(
+    tx: &mut sqlx::Transaction<'_, Postgres>,
+    idempotency_key: &str,
+    outcome: &Result<(), TransactError>,
+) -> Result<(), sqlx::Error> {
+    let (status, detail) = match outcome {
+        Ok(()) => (Status::Confirmed, None),
+        Err(e) => (Status::Failed, Some(e.to_string())),
+    };
 
-    // 4. Commit
-    tx.commit().await.map_err(|e| e.to_string())?;
+    sqlx::query(
+        "UPDATE transactions SET status = $1, detail = $2, updated_at = now() WHERE idempotency_key = $3",
+    )
+    .bind(status.as_str())
+    .bind(detail)
+    .bind(idempotency_key)
+    .execute(&mut **tx)
+    .await?;
 
     Ok(())
 }
 
-// --- 5. Updated Test Module for SQLx 0.8 ---
+/// Runs a batch of independent transactions inside a single atomic Postgres
+/// transaction, fetching every account any of them touches with one
+/// `ANY($1)` query instead of one `FOR UPDATE` round trip per transaction.
+/// By default one entry's business-rule rejection (insufficient funds,
+/// unknown account, ...) doesn't roll back the rest of the batch — its
+/// `Result` just comes back `Err` while its siblings still commit. Pass
+/// `all_or_nothing: true` to roll back the whole batch instead the moment
+/// any entry fails.
+///
+/// Each entry's `idempotency_key` is claimed (see `claim_or_replay`) before
+/// any balance is touched, in the same transaction that applies it, so the
+/// claim and the money movement always commit or roll back together.
+///
+/// The outer `Result` is for failures that keep the batch from running at
+/// all (e.g. the connection dropping before the `ANY($1)` lookup); the
+/// per-entry `Result`s inside are each transaction's own outcome.
+async fn transact_batch_once(
+    pool: &PgPool,
+    entries: &[(String, Transaction)],
+    serializable: bool,
+    all_or_nothing: bool,
+) -> Result<Vec<(Uuid, Result<(), TransactError>)>, TransactError> {
+    let mut tx = pool.begin().await?;
+
+    if serializable {
+        sqlx::query("SET TRANSACTION ISOLATION LEVEL SERIALIZABLE")
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    let mut results: Vec<(Uuid, Result<(), TransactError>)> = Vec::with_capacity(entries.len());
+    let mut fresh: Vec<bool> = Vec::with_capacity(entries.len());
+
+    for (idempotency_key, transaction) in entries {
+        match claim_or_replay(&mut tx, transaction.id(), idempotency_key).await? {
+            Some(outcome) => {
+                results.push((transaction.id(), outcome));
+                fresh.push(false);
+            }
+            None => {
+                // Applied below, once every key in the batch has a claim;
+                // placeholder so `results[i]` lines up with `entries[i]`.
+                results.push((transaction.id(), Ok(())));
+                fresh.push(true);
+            }
+        }
+    }
+
+    let to_apply: Vec<Transaction> = entries
+        .iter()
+        .zip(&fresh)
+        .filter(|(_, &is_fresh)| is_fresh)
+        .map(|((_, transaction), _)| transaction.clone())
+        .collect();
+    let account_ids = distinct_account_ids_across(&to_apply);
+    let mut balances = lock_balances_batch(&mut tx, &account_ids).await?;
+
+    let mut any_failed = false;
+    for (index, (idempotency_key, transaction)) in entries.iter().enumerate() {
+        if !fresh[index] {
+            any_failed |= results[index].1.is_err();
+            continue;
+        }
+
+        let outcome = apply_one(&mut tx, transaction, &mut balances).await;
+        any_failed |= outcome.is_err();
+        record_outcome(&mut tx, idempotency_key, &outcome).await?;
+        results[index] = (transaction.id(), outcome);
+    }
+
+    if all_or_nothing && any_failed {
+        tx.rollback().await?;
+        return Ok(results
+            .into_iter()
+            .zip(&fresh)
+            .map(|((id, outcome), &is_fresh)| {
+                if is_fresh && outcome.is_ok() {
+                    (
+                        id,
+                        Err(TransactError::Business(
+                            "Rolled back: another transaction in the batch failed".to_string(),
+                        )),
+                    )
+                } else {
+                    (id, outcome)
+                }
+            })
+            .collect());
+    }
+
+    tx.commit().await?;
+    Ok(results)
+}
+
+/// Runs `transact_batch_once`, retrying the whole batch up to
+/// `MAX_SERIALIZATION_RETRIES` times with a small exponential backoff
+/// whenever `serializable` is set and Postgres reports a `40001`
+/// serialization failure — the same policy `transact_with_options` applies
+/// to a single transfer. Safe to retry wholesale: a `40001` aborts the
+/// transaction Postgres was building, which rolls back every claim and
+/// balance update the failed attempt had made, so the retry starts clean.
+pub async fn transact_batch(
+    pool: &PgPool,
+    entries: &[(String, Transaction)],
+    serializable: bool,
+    all_or_nothing: bool,
+) -> Result<Vec<(Uuid, Result<(), TransactError>)>, TransactError> {
+    let mut attempt = 0;
+    loop {
+        match transact_batch_once(pool, entries, serializable, all_or_nothing).await {
+            Ok(results) => return Ok(results),
+            Err(e) if serializable && e.is_serialization_failure() && attempt < MAX_SERIALIZATION_RETRIES => {
+                attempt += 1;
+                let backoff = Duration::from_millis(20 * 2u64.pow(attempt));
+                tokio::time::sleep(backoff).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn transact_once(
+    pool: &PgPool,
+    transaction: &Transaction,
+    serializable: bool,
+) -> Result<(), TransactError> {
+    let mut tx = pool.begin().await?;
+
+    if serializable {
+        sqlx::query("SET TRANSACTION ISOLATION LEVEL SERIALIZABLE")
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    let account_ids = distinct_account_ids(transaction);
+    let balances = lock_balances(&mut tx, &account_ids).await?;
+
+    let mut net_by_account: HashMap<AccountId, Decimal> = HashMap::new();
+    for leg in transaction.legs() {
+        *net_by_account.entry(leg.account_id()).or_insert(Decimal::ZERO) += leg.amount();
+    }
+
+    for (account_id, delta) in &net_by_account {
+        let balance = balances[account_id];
+        if balance + *delta < Decimal::ZERO {
+            return Err(TransactError::Business(format!(
+                "Account {} has insufficient funds",
+                account_id
+            )));
+        }
+    }
+
+    push_transaction(&mut tx, transaction).await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Runs `transact_once` at the default isolation level, without
+/// `SERIALIZABLE` and so without the `40001` retry of
+/// `transact_with_options`. Business-rule rejections (insufficient funds,
+/// unknown account, unbalanced legs, ...) are never retried.
+pub async fn transact(pool: &PgPool, transaction: Transaction) -> Result<(), TransactError> {
+    transact_with_options(pool, transaction, false).await
+}
+
+/// Same as `transact`, but lets the caller opt into `SERIALIZABLE` isolation
+/// for the underlying transaction; with it set, a `40001` serialization
+/// failure is retried up to `MAX_SERIALIZATION_RETRIES` times with a small
+/// exponential backoff.
+pub async fn transact_with_options(
+    pool: &PgPool,
+    transaction: Transaction,
+    serializable: bool,
+) -> Result<(), TransactError> {
+    verify(&transaction).map_err(TransactError::Business)?;
+
+    let mut attempt = 0;
+    loop {
+        match transact_once(pool, &transaction, serializable).await {
+            Ok(()) => return Ok(()),
+            Err(e) if serializable && e.is_serialization_failure() && attempt < MAX_SERIALIZATION_RETRIES => {
+                attempt += 1;
+                let backoff = Duration::from_millis(20 * 2u64.pow(attempt));
+                tokio::time::sleep(backoff).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::models::transaction::Transaction;
+    use crate::models::transaction::{Leg, Transaction};
+    use chrono::Utc;
     use rust_decimal_macros::dec;
     use sqlx::PgPool;
+    use uuid::Uuid;
 
     async fn setup_schema(pool: &PgPool) {
         sqlx::query("CREATE TABLE IF NOT EXISTS accounts (id INT PRIMARY KEY, balance DECIMAL)")
             .execute(pool)
             .await
-            .expect("Failed to create schema");
+            .expect("Failed to create accounts table");
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS postings (
+                transaction_id UUID NOT NULL,
+                account_id INT NOT NULL,
+                amount DECIMAL NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL
+            )",
+        )
+        .execute(pool)
+        .await
+        .expect("Failed to create postings table");
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS transactions (
+                id UUID PRIMARY KEY,
+                idempotency_key TEXT NOT NULL UNIQUE,
+                status TEXT NOT NULL,
+                detail TEXT,
+                updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )",
+        )
+        .execute(pool)
+        .await
+        .expect("Failed to create transactions table");
+    }
+
+    #[test]
+    fn test_verify_rejects_single_leg() {
+        let transaction = Transaction::new(Uuid::new_v4(), Utc::now(), vec![Leg::new(1, dec!(10.00), "USD")]);
+        let res = verify(&transaction);
+        assert_eq!(res.unwrap_err(), "Transaction must have at least two legs");
+    }
+
+    #[test]
+    fn test_verify_rejects_unbalanced_legs() {
+        let transaction = Transaction::new(Uuid::new_v4(), Utc::now(), vec![
+            Leg::new(1, dec!(-10.00), "USD"),
+            Leg::new(2, dec!(5.00), "USD"),
+        ]);
+        let res = verify(&transaction);
+        assert_eq!(res.unwrap_err(), "Transaction legs must net to zero");
+    }
+
+    #[test]
+    fn test_verify_allows_unbalanced_legs_across_currencies() {
+        let transaction = Transaction::exchange(
+            Uuid::new_v4(), Utc::now(),
+            1, "USD", dec!(100.00),
+            2, "EUR", dec!(85.00),
+        );
+        assert!(verify(&transaction).is_ok());
     }
 
     #[sqlx::test]
-    async fn test_verify_insufficient_funds(pool: PgPool) {
+    async fn test_transact_insufficient_funds(pool: PgPool) {
         setup_schema(&pool).await;
-        
+
         sqlx::query("INSERT INTO accounts (id, balance) VALUES ($1, $2), ($3, $4)")
             .bind(1).bind(dec!(50.00))
             .bind(2).bind(dec!(200.00))
@@ -97,34 +581,30 @@ mod tests {
             .await
             .unwrap();
 
-        let transaction = Transaction::new(
-            1, 2, dec!(100.00)
-        );
+        let transaction = Transaction::transfer(Uuid::new_v4(), Utc::now(), 1, 2, dec!(100.00), "USD");
 
-        let res = verify(&pool, &transaction).await;
-        assert_eq!(res.unwrap_err(), "Source account has insufficient funds");
+        let res = transact(&pool, transaction).await;
+        assert_eq!(res.unwrap_err().to_string(), "Account 1 has insufficient funds");
     }
 
     #[sqlx::test]
-    async fn test_verify_source_account_not_found(pool: PgPool) {
+    async fn test_transact_source_account_not_found(pool: PgPool) {
         setup_schema(&pool).await;
-        
+
         sqlx::query("INSERT INTO accounts (id, balance) VALUES ($1, $2)")
             .bind(2).bind(dec!(200.00))
             .execute(&pool)
             .await
             .unwrap();
 
-        let transaction = Transaction::new(
-             99, 2, dec!(100.00)
-        );
+        let transaction = Transaction::transfer(Uuid::new_v4(), Utc::now(), 99, 2, dec!(100.00), "USD");
 
-        let res = verify(&pool, &transaction).await;
-        assert_eq!(res.unwrap_err(), "Source account not found");
+        let res = transact(&pool, transaction).await;
+        assert_eq!(res.unwrap_err().to_string(), "Account 99 not found");
     }
 
     #[sqlx::test]
-    async fn test_successful_transaction(pool: PgPool) {
+    async fn test_successful_transaction_writes_postings(pool: PgPool) {
         setup_schema(&pool).await;
 
         sqlx::query("INSERT INTO accounts (id, balance) VALUES ($1, $2), ($3, $4)")
@@ -133,13 +613,11 @@ mod tests {
             .execute(&pool)
             .await
             .unwrap();
-        
-        let transaction = Transaction::new(
-            1, 2, dec!(25.00)
-        );
 
-        let res = transact(&pool, transaction).await;
+        let transaction = Transaction::transfer(Uuid::new_v4(), Utc::now(), 1, 2, dec!(25.00), "USD");
+        let transaction_id = transaction.id();
 
+        let res = transact(&pool, transaction).await;
         assert!(res.is_ok());
 
         let new_source_bal: Decimal = sqlx::query_scalar("SELECT balance FROM accounts WHERE id = 1")
@@ -149,5 +627,84 @@ mod tests {
 
         assert_eq!(new_source_bal, dec!(75.00));
         assert_eq!(new_target_bal, dec!(75.00));
+
+        let posting_count: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM postings WHERE transaction_id = $1")
+                .bind(transaction_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(posting_count, 2);
+    }
+
+    #[sqlx::test]
+    async fn test_transact_supports_multi_leg_split_settlement(pool: PgPool) {
+        setup_schema(&pool).await;
+
+        sqlx::query("INSERT INTO accounts (id, balance, currency) VALUES ($1, $2, 'USD'), ($3, $4, 'USD'), ($5, $6, 'USD')")
+            .bind(1).bind(dec!(100.00))
+            .bind(2).bind(dec!(0.00))
+            .bind(3).bind(dec!(0.00))
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        // Source pays 100, split as 90 to the payee and a 10 fee.
+        let transaction = Transaction::new(
+            Uuid::new_v4(),
+            Utc::now(),
+            vec![
+                Leg::new(1, dec!(-100.00), "USD"),
+                Leg::new(2, dec!(90.00), "USD"),
+                Leg::new(3, dec!(10.00), "USD"),
+            ],
+        );
+
+        let res = transact(&pool, transaction).await;
+        assert!(res.is_ok());
+
+        let source_bal: Decimal = sqlx::query_scalar("SELECT balance FROM accounts WHERE id = 1")
+            .fetch_one(&pool).await.unwrap();
+        let payee_bal: Decimal = sqlx::query_scalar("SELECT balance FROM accounts WHERE id = 2")
+            .fetch_one(&pool).await.unwrap();
+        let fee_bal: Decimal = sqlx::query_scalar("SELECT balance FROM accounts WHERE id = 3")
+            .fetch_one(&pool).await.unwrap();
+
+        assert_eq!(source_bal, dec!(0.00));
+        assert_eq!(payee_bal, dec!(90.00));
+        assert_eq!(fee_bal, dec!(10.00));
     }
-}
\ No newline at end of file
+
+    #[sqlx::test]
+    async fn test_transact_batch_does_not_reapply_a_confirmed_idempotency_key(pool: PgPool) {
+        setup_schema(&pool).await;
+
+        sqlx::query("INSERT INTO accounts (id, balance, currency) VALUES ($1, $2, 'USD'), ($3, $4, 'USD')")
+            .bind(1).bind(dec!(100.00))
+            .bind(2).bind(dec!(0.00))
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let transaction = Transaction::transfer(Uuid::new_v4(), Utc::now(), 1, 2, dec!(25.00), "USD");
+        let entries = vec![("retry-me".to_string(), transaction)];
+
+        let first = transact_batch(&pool, &entries, false, false).await.unwrap();
+        assert!(first[0].1.is_ok());
+
+        // A redelivery of the same idempotency key — same id, fresh batch —
+        // must not move the money again even though it's never seen this
+        // `Transaction` value before; the claim was already settled by the
+        // first attempt's commit.
+        let second = transact_batch(&pool, &entries, false, false).await.unwrap();
+        assert!(second[0].1.is_ok());
+
+        let source_bal: Decimal = sqlx::query_scalar("SELECT balance FROM accounts WHERE id = 1")
+            .fetch_one(&pool).await.unwrap();
+        let target_bal: Decimal = sqlx::query_scalar("SELECT balance FROM accounts WHERE id = 2")
+            .fetch_one(&pool).await.unwrap();
+
+        assert_eq!(source_bal, dec!(75.00));
+        assert_eq!(target_bal, dec!(25.00));
+    }
+}
@@ -1,23 +1,63 @@
 use clap::Parser;
 use futures_lite::stream::StreamExt;
 use lapin::{
-    options::{BasicAckOptions, BasicConsumeOptions, BasicNackOptions, QueueDeclareOptions},
-    types::FieldTable,
-    Connection, ConnectionProperties,
+    message::Delivery,
+    options::{
+        BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicPublishOptions,
+        QueueDeclareOptions,
+    },
+    types::{AMQPValue, FieldTable},
+    BasicProperties, Channel, Connection, ConnectionProperties,
 };
-use redis::AsyncCommands; // For updating status
 use rust_decimal::Decimal;
 use serde::Deserialize;
 use sqlx::postgres::PgPoolOptions;
+use sqlx::PgPool;
 use std::error::Error;
+use std::time::Duration;
 use uuid::Uuid;
 use chrono::Utc;
 
-mod core; 
+mod core;
 mod models;
 
+use crate::core::worker::{self, TransactError};
+use crate::core::{exchange, rates, retry, status};
+use crate::core::idempotency::{self, Claim};
+use crate::models::status::Status;
 use crate::models::transaction::Transaction;
-use crate::core::worker::transact;
+
+const QUEUE_NAME: &str = "transactions";
+
+/// Where a transfer goes once it has exhausted `max_delivery_attempts`
+/// transient retries, so a stuck dependency doesn't spin the main queue
+/// forever. Inspected by hand, or drained by a separate recovery job.
+const DEAD_LETTER_QUEUE_NAME: &str = "transactions.dead_letter";
+
+/// Holding queue for a transient failure's backoff delay. Declared with no
+/// consumer and `x-dead-letter-exchange`/`x-dead-letter-routing-key`
+/// pointing back at `QUEUE_NAME`, so a message only ever leaves it by
+/// expiring (via the per-message `expiration` property set to that
+/// attempt's `retry::backoff`) and being dead-lettered back onto the main
+/// queue. This is what lets a transient failure back off without blocking
+/// the consumer loop on `tokio::time::sleep` — the delay is the broker's
+/// problem, not ours.
+const RETRY_QUEUE_NAME: &str = "transactions.retry";
+
+/// How many deliveries the worker folds into a single `transact_batch`
+/// call. Bigger batches amortize the `ANY($1)` balance lookup over more
+/// transfers, but also widen the `FOR UPDATE` lock held per account.
+const BATCH_SIZE: usize = 20;
+
+/// How long to wait for one more delivery before running whatever's been
+/// drained so far as a (possibly smaller) batch, so a quiet queue doesn't
+/// leave the first arrival waiting on `BATCH_SIZE` more that never come.
+const BATCH_LINGER: Duration = Duration::from_millis(50);
+
+/// How often the background task re-seeds `rates:current`. Exchange rates
+/// drift slowly enough that a minute-scale refresh is plenty fresh for
+/// settlement purposes, while keeping the write volume against Redis low.
+const RATES_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(Debug, Deserialize)]
 struct TransferRequestDto {
@@ -38,6 +78,192 @@ struct Args {
 
     #[arg(long, default_value = "redis://localhost:6379/")]
     redis_url: String,
+
+    /// Opt into SERIALIZABLE isolation for each transfer's transaction,
+    /// retrying on Postgres `40001` serialization failures instead of
+    /// relying solely on row-level `FOR UPDATE` locks.
+    #[arg(long, default_value_t = false)]
+    serializable: bool,
+
+    /// How many times a job may be sent back through the retry queue after a
+    /// transient failure (a dropped connection, a serialization conflict)
+    /// before it's moved to the dead-letter queue and marked `Failed`.
+    #[arg(long, default_value_t = 5)]
+    max_delivery_attempts: u32,
+}
+
+/// A delivery that's already claimed its idempotency key and had its
+/// `Transaction` built, waiting to run through `transact_batch` with the
+/// rest of the deliveries drained alongside it.
+struct PendingTransfer {
+    delivery: Delivery,
+    idempotency_key: String,
+    transaction: Transaction,
+}
+
+/// `TransactError`, minus the original `sqlx::Error`, so the same failure
+/// can be reported for every pending delivery in a batch at once. Needed
+/// because a whole-batch failure (the connection dropping before the
+/// `ANY($1)` lookup even ran) applies to every entry, and `TransactError`
+/// isn't `Clone`.
+enum TransferFailure {
+    Transient(String),
+    Business(String),
+}
+
+impl TransferFailure {
+    fn is_transient(&self) -> bool {
+        matches!(self, TransferFailure::Transient(_))
+    }
+}
+
+impl std::fmt::Display for TransferFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransferFailure::Transient(msg) => write!(f, "{}", msg),
+            TransferFailure::Business(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl From<TransactError> for TransferFailure {
+    fn from(e: TransactError) -> Self {
+        if e.is_transient() {
+            TransferFailure::Transient(e.to_string())
+        } else {
+            TransferFailure::Business(e.to_string())
+        }
+    }
+}
+
+/// Drains up to `BATCH_SIZE` deliveries off `consumer`, waiting at most
+/// `BATCH_LINGER` for each one after the first so a batch still flushes
+/// promptly when the queue is quiet.
+async fn drain_batch(
+    consumer: &mut lapin::Consumer,
+) -> Vec<Result<Delivery, lapin::Error>> {
+    let mut deliveries = Vec::with_capacity(BATCH_SIZE);
+
+    if let Some(first) = consumer.next().await {
+        deliveries.push(first);
+    }
+
+    while deliveries.len() < BATCH_SIZE {
+        match tokio::time::timeout(BATCH_LINGER, consumer.next()).await {
+            Ok(Some(next)) => deliveries.push(next),
+            _ => break,
+        }
+    }
+
+    deliveries
+}
+
+/// Records the outcome of one transfer (Redis status, Postgres status,
+/// idempotency marker) and acks/nacks its delivery accordingly. Shared by
+/// both the batched path and the "failed before it could even join a
+/// batch" path (malformed JSON aside, which never reaches here).
+async fn handle_outcome(
+    pool: &PgPool,
+    redis_conn: &mut redis::aio::MultiplexedConnection,
+    channel: &Channel,
+    max_delivery_attempts: u32,
+    delivery: Delivery,
+    idempotency_key: &str,
+    transaction_id: Uuid,
+    result: Result<(), TransferFailure>,
+) -> Result<(), Box<dyn Error>> {
+    match result {
+        Ok(_) => {
+            println!("💰 [{}] Database Transaction Committed.", idempotency_key);
+
+            if let Err(e) = idempotency::mark_confirmed(redis_conn, idempotency_key).await {
+                eprintln!("❌ REDIS WRITE FAILED: {}", e);
+            }
+            if let Err(e) = status::record(pool, transaction_id, idempotency_key, Status::Confirmed, None).await {
+                eprintln!("❌ Failed to persist Confirmed status: {}", e);
+            }
+
+            delivery.ack(BasicAckOptions::default()).await?;
+        }
+        Err(e) if e.is_transient() => {
+            let detail = e.to_string();
+            eprintln!("⚠️ [{}] Transient failure: {}", idempotency_key, detail);
+
+            let attempt = match retry::record_attempt(redis_conn, idempotency_key).await {
+                Ok(attempt) => attempt,
+                Err(e) => {
+                    eprintln!("❌ Failed to record delivery attempt in Redis: {}", e);
+                    max_delivery_attempts + 1 // fail safe: dead-letter rather than retry forever
+                }
+            };
+
+            if attempt <= max_delivery_attempts {
+                println!("🔁 [{}] Delaying retry {}/{}", idempotency_key, attempt, max_delivery_attempts);
+
+                if let Err(e) = idempotency::mark_delayed(redis_conn, idempotency_key, &detail).await {
+                    eprintln!("❌ REDIS WRITE FAILED: {}", e);
+                }
+                if let Err(e) = status::record(pool, transaction_id, idempotency_key, Status::Delayed, Some(&detail)).await {
+                    eprintln!("❌ Failed to persist Delayed status: {}", e);
+                }
+
+                // Publish a copy onto `transactions.retry` with this
+                // attempt's backoff as its expiration, then ack the
+                // original: the retry queue's dead-letter config bounces
+                // the message back onto `QUEUE_NAME` once it expires. No
+                // `sleep` here — blocking this task would stall every
+                // other delivery waiting on the same consumer loop.
+                let delay_ms = retry::backoff(attempt).as_millis().to_string();
+                channel
+                    .basic_publish(
+                        "",
+                        RETRY_QUEUE_NAME,
+                        BasicPublishOptions::default(),
+                        &delivery.data,
+                        BasicProperties::default().with_expiration(delay_ms.into()),
+                    )
+                    .await?;
+                delivery.ack(BasicAckOptions::default()).await?;
+            } else {
+                let detail = format!("exceeded {} delivery attempts: {}", max_delivery_attempts, detail);
+                eprintln!("☠️ [{}] {}, dead-lettering", idempotency_key, detail);
+
+                if let Err(e) = idempotency::mark_failed(redis_conn, idempotency_key, &detail).await {
+                    eprintln!("❌ REDIS WRITE FAILED: {}", e);
+                }
+                if let Err(e) = status::record(pool, transaction_id, idempotency_key, Status::Failed, Some(&detail)).await {
+                    eprintln!("❌ Failed to persist Failed status: {}", e);
+                }
+
+                channel
+                    .basic_publish(
+                        "",
+                        DEAD_LETTER_QUEUE_NAME,
+                        BasicPublishOptions::default(),
+                        &delivery.data,
+                        BasicProperties::default(),
+                    )
+                    .await?;
+
+                delivery.ack(BasicAckOptions::default()).await?;
+            }
+        }
+        Err(e) => {
+            let detail = e.to_string();
+            eprintln!("⚠️ [{}] Transaction rejected: {}", idempotency_key, detail);
+
+            if let Err(e) = idempotency::mark_failed(redis_conn, idempotency_key, &detail).await {
+                eprintln!("❌ REDIS WRITE FAILED: {}", e);
+            }
+            if let Err(e) = status::record(pool, transaction_id, idempotency_key, Status::Failed, Some(&detail)).await {
+                eprintln!("❌ Failed to persist Failed status: {}", e);
+            }
+
+            delivery.ack(BasicAckOptions::default()).await?;
+        }
+    }
+
+    Ok(())
 }
 
 #[tokio::main]
@@ -53,21 +279,39 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .await?;
     println!("✅ Connected to Postgres");
 
+    sqlx::migrate!("./migrations").run(&pool).await?;
+    println!("✅ Ran database migrations");
+
     // 2. Connect to Redis
     let redis_client = redis::Client::open(args.redis_url.clone())?;
     // We use multiplexed connection which is standard for redis-rs 0.24+
     let mut redis_conn = redis_client.get_multiplexed_async_connection().await?;
     println!("✅ Connected to Redis at {}", args.redis_url);
 
+    // Keep `rates:current` populated on its own connection/timer so a quiet
+    // transaction queue doesn't leave it stale, and so the refresh never
+    // contends with the main loop's connection.
+    let mut rates_conn = redis_client.get_multiplexed_async_connection().await?;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RATES_REFRESH_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = rates::refresh(&mut rates_conn).await {
+                eprintln!("❌ Failed to refresh exchange rates: {}", e);
+            }
+        }
+    });
+    println!("✅ Started exchange rate refresh task");
+
     // 3. Connect to RabbitMQ
     let conn = Connection::connect(&args.amqp_addr, ConnectionProperties::default()).await?;
     let channel = conn.create_channel().await?;
     println!("✅ Connected to RabbitMQ");
 
-    // 4. Declare Queue
+    // 4. Declare Queues
     let _queue = channel
         .queue_declare(
-            "transactions",
+            QUEUE_NAME,
             QueueDeclareOptions {
                 durable: true,
                 ..QueueDeclareOptions::default()
@@ -76,10 +320,41 @@ async fn main() -> Result<(), Box<dyn Error>> {
         )
         .await?;
 
+    let _dead_letter_queue = channel
+        .queue_declare(
+            DEAD_LETTER_QUEUE_NAME,
+            QueueDeclareOptions {
+                durable: true,
+                ..QueueDeclareOptions::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+
+    let mut retry_queue_args = FieldTable::default();
+    retry_queue_args.insert(
+        "x-dead-letter-exchange".into(),
+        AMQPValue::LongString("".into()),
+    );
+    retry_queue_args.insert(
+        "x-dead-letter-routing-key".into(),
+        AMQPValue::LongString(QUEUE_NAME.into()),
+    );
+    let _retry_queue = channel
+        .queue_declare(
+            RETRY_QUEUE_NAME,
+            QueueDeclareOptions {
+                durable: true,
+                ..QueueDeclareOptions::default()
+            },
+            retry_queue_args,
+        )
+        .await?;
+
     // 5. Create Consumer
     let mut consumer = channel
         .basic_consume(
-            "transactions",
+            QUEUE_NAME,
             "rust_worker_debug", // Specific tag for this worker
             BasicConsumeOptions::default(),
             FieldTable::default(),
@@ -88,8 +363,24 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     println!("🎧 Waiting for transactions...");
 
-    while let Some(delivery) = consumer.next().await {
-        if let Ok(delivery) = delivery {
+    loop {
+        let deliveries = drain_batch(&mut consumer).await;
+        if deliveries.is_empty() {
+            // The consumer stream ended (channel/connection closed).
+            break;
+        }
+
+        println!("---------------------------------------------------");
+        println!("📥 Draining batch of {} deliveries", deliveries.len());
+
+        let mut pending: Vec<PendingTransfer> = Vec::with_capacity(deliveries.len());
+
+        for delivery in deliveries {
+            let delivery = match delivery {
+                Ok(delivery) => delivery,
+                Err(_) => continue,
+            };
+
             let req: TransferRequestDto = match serde_json::from_slice(&delivery.data) {
                 Ok(data) => data,
                 Err(e) => {
@@ -99,47 +390,124 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 }
             };
 
-            println!("---------------------------------------------------");
-            println!("📥 Processing Job [{}]", req.idempotency_key);
+            // Claim the idempotency key before touching money: a redelivered
+            // AMQP message or a client retry must not apply the same
+            // transfer twice.
+            match idempotency::claim(&mut redis_conn, &req.idempotency_key).await {
+                Ok(Claim::AlreadyCompleted(outcome)) => {
+                    println!("♻️ [{}] Already processed, replaying stored status: {}", req.idempotency_key, outcome);
+                    delivery.ack(BasicAckOptions::default()).await?;
+                    continue;
+                }
+                Ok(Claim::InProgress) => {
+                    println!("⏳ [{}] Another delivery is still processing this key, waiting before requeueing", req.idempotency_key);
+                    // Same backoff curve as a transient-failure retry, so a
+                    // slow in-flight delivery doesn't get hammered by an
+                    // immediate redelivery spinning tight on `InProgress`.
+                    tokio::time::sleep(retry::backoff(1)).await;
+                    delivery.nack(BasicNackOptions { requeue: true, ..Default::default() }).await?;
+                    continue;
+                }
+                Ok(Claim::Acquired) => {}
+                Err(e) => {
+                    eprintln!("❌ Failed to claim idempotency key in Redis: {}", e);
+                    delivery.nack(BasicNackOptions { requeue: true, ..Default::default() }).await?;
+                    continue;
+                }
+            }
 
-            let transaction = Transaction::new(
-                Uuid::new_v4(),
+            let transaction_id = idempotency::derive_transaction_id(&req.idempotency_key);
+            if let Err(e) = status::record(&pool, transaction_id, &req.idempotency_key, Status::Pending, None).await {
+                eprintln!("❌ Failed to persist Pending status: {}", e);
+            }
+
+            let built = exchange::build_transfer(
+                &pool,
+                &mut redis_conn,
+                transaction_id,
                 Utc::now(),
                 req.source_id,
                 req.target_id,
-                req.amount
-            );
-
-            let redis_key = format!("status:{}", req.idempotency_key);
-            
-            match transact(&pool, transaction).await {
-                Ok(_) => {
-                    println!("💰 Database Transaction Committed.");
-                    
-                    println!("📝 Attempting to write 'success' to Redis key: {}", redis_key);
-                    
-                    // Explicitly handling Redis errors (No more silent failures)
-                    match redis_conn.set::<_, _, ()>(&redis_key, "success").await {
-                        Ok(_) => println!("✅ Redis Update Successful"),
-                        Err(e) => println!("❌ REDIS WRITE FAILED: {}", e),
-                    }
-                    
-                    delivery.ack(BasicAckOptions::default()).await?;
-                }
+                req.amount,
+            )
+            .await;
+
+            match built {
+                Ok(transaction) => pending.push(PendingTransfer {
+                    delivery,
+                    idempotency_key: req.idempotency_key,
+                    transaction,
+                }),
                 Err(e) => {
-                    eprintln!("⚠️ Transaction Logic Failed: {}", e);
-                    
-                    println!("📝 Writing failure reason to Redis...");
-                    match redis_conn.set::<_, _, ()>(&redis_key, format!("failed: {}", e)).await {
-                        Ok(_) => println!("✅ Redis Update Successful"),
-                        Err(e) => println!("❌ REDIS WRITE FAILED: {}", e),
-                    }
-                    
-                    delivery.ack(BasicAckOptions::default()).await?;
+                    handle_outcome(
+                        &pool,
+                        &mut redis_conn,
+                        &channel,
+                        args.max_delivery_attempts,
+                        delivery,
+                        &req.idempotency_key,
+                        transaction_id,
+                        Err(e.into()),
+                    )
+                    .await?;
+                }
+            }
+        }
+
+        if pending.is_empty() {
+            continue;
+        }
+
+        let entries: Vec<(String, Transaction)> = pending
+            .iter()
+            .map(|p| (p.idempotency_key.clone(), p.transaction.clone()))
+            .collect();
+
+        // One `ANY($1)` balance lookup and one atomic Postgres transaction
+        // for the whole batch, instead of one round trip per transfer. Each
+        // entry's idempotency key is claimed inside that same transaction
+        // (see `worker::claim_or_replay`), so the claim and the transfer
+        // always commit or roll back together.
+        let batch_result = worker::transact_batch(&pool, &entries, args.serializable, false).await;
+
+        match batch_result {
+            Ok(results) => {
+                for (pending, (transaction_id, result)) in pending.into_iter().zip(results) {
+                    handle_outcome(
+                        &pool,
+                        &mut redis_conn,
+                        &channel,
+                        args.max_delivery_attempts,
+                        pending.delivery,
+                        &pending.idempotency_key,
+                        transaction_id,
+                        result.map_err(TransferFailure::from),
+                    )
+                    .await?;
+                }
+            }
+            Err(e) => {
+                // The batch never got to run at all (e.g. the connection
+                // dropped before the `ANY($1)` lookup); every entry in it
+                // failed for the same transient reason.
+                let detail = TransferFailure::from(e).to_string();
+                for p in pending {
+                    let transaction_id = p.transaction.id();
+                    handle_outcome(
+                        &pool,
+                        &mut redis_conn,
+                        &channel,
+                        args.max_delivery_attempts,
+                        p.delivery,
+                        &p.idempotency_key,
+                        transaction_id,
+                        Err(TransferFailure::Transient(detail.clone())),
+                    )
+                    .await?;
                 }
             }
         }
     }
 
     Ok(())
-}
\ No newline at end of file
+}
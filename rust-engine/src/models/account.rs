@@ -1,30 +1,35 @@
 use rust_decimal::Decimal;
 
 #[derive(Debug, sqlx::FromRow)]
-
-struct Account {
+pub struct Account {
     account_id: i32,    // SERIAL (int4) maps to i32
     value: Decimal,     // This type is precise and safe for money
+    currency: String,   // ISO 4217 code, e.g. "USD"; fixes the unit `value` is denominated in
 }
 
-
 impl Account {
 
     #[allow(dead_code)]
-    pub fn new(account_id: i32, value: Decimal) -> Self {
+    pub fn new(account_id: i32, value: Decimal, currency: impl Into<String>) -> Self {
         Account {
             account_id,
-            value
+            value,
+            currency: currency.into(),
         }
     }
     #[allow(dead_code)]
     pub fn account_id(&self) -> i32 {
         self.account_id
     }
-    
+
     #[allow(dead_code)]
     pub fn value(&self) -> Decimal {
         self.value
     }
 
-}
\ No newline at end of file
+    #[allow(dead_code)]
+    pub fn currency(&self) -> &str {
+        &self.currency
+    }
+
+}
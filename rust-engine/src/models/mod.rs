@@ -0,0 +1,4 @@
+pub mod account;
+pub mod posting;
+pub mod status;
+pub mod transaction;
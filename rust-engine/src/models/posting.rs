@@ -0,0 +1,43 @@
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use uuid::Uuid;
+
+/// A single immutable entry in the append-only `postings` table: which
+/// transaction it belongs to, which account it touched, the signed amount
+/// (positive credits, negative debits), and when it was written. Balances
+/// can always be reconstructed by summing an account's postings, so this
+/// is the source of truth the `accounts.balance` column is a cache of.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Posting {
+    transaction_id: Uuid,
+    account_id: i32,
+    amount: Decimal,
+    created_at: DateTime<Utc>,
+}
+
+impl Posting {
+    pub fn new(transaction_id: Uuid, account_id: i32, amount: Decimal, created_at: DateTime<Utc>) -> Self {
+        Posting {
+            transaction_id,
+            account_id,
+            amount,
+            created_at,
+        }
+    }
+
+    pub fn transaction_id(&self) -> Uuid {
+        self.transaction_id
+    }
+
+    pub fn account_id(&self) -> i32 {
+        self.account_id
+    }
+
+    pub fn amount(&self) -> Decimal {
+        self.amount
+    }
+
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+}
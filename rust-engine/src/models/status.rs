@@ -0,0 +1,37 @@
+use std::fmt;
+
+/// Where a transfer currently stands in its lifecycle, as surfaced to
+/// external pollers via the Redis `status:{idempotency_key}` entry and the
+/// `transfer_status.status` column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Parsed off the queue, not yet claimed for processing.
+    Proposed,
+    /// Claimed and currently being applied.
+    Pending,
+    /// Applied successfully; terminal.
+    Confirmed,
+    /// Rejected by a business rule, or a transient failure that ran out of
+    /// retries; terminal.
+    Failed,
+    /// Hit a transient failure and is waiting to be retried; not terminal.
+    Delayed,
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Proposed => "proposed",
+            Status::Pending => "pending",
+            Status::Confirmed => "confirmed",
+            Status::Failed => "failed",
+            Status::Delayed => "delayed",
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
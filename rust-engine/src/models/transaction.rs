@@ -1,34 +1,135 @@
+use chrono::{DateTime, Utc};
 use rust_decimal::Decimal;
+use uuid::Uuid;
 
-type AccountId = i32;
+pub type AccountId = i32;
 
-#[derive(Clone)]
+/// One signed movement against a single account within a `Transaction`. A
+/// positive `amount` credits the account, a negative `amount` debits it.
+/// `currency` is the ISO 4217 code `amount` is denominated in, which is
+/// normally the account's own currency. Legs are the unit the ledger
+/// actually persists (see `models::posting`); a `Transaction` is just the
+/// set of legs that must clear together.
+#[derive(Clone, Debug)]
+pub struct Leg {
+    account_id: AccountId,
+    amount: Decimal,
+    currency: String,
+}
+
+impl Leg {
+    pub fn new(account_id: AccountId, amount: Decimal, currency: impl Into<String>) -> Self {
+        Leg {
+            account_id,
+            amount,
+            currency: currency.into(),
+        }
+    }
+
+    pub fn account_id(&self) -> AccountId {
+        self.account_id
+    }
+
+    pub fn amount(&self) -> Decimal {
+        self.amount
+    }
+
+    pub fn currency(&self) -> &str {
+        &self.currency
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Transaction {
-    source: AccountId,
-    target: AccountId,
-    value: Decimal
+    id: Uuid,
+    created_at: DateTime<Utc>,
+    legs: Vec<Leg>,
 }
 
 impl Transaction {
+    pub fn new(id: Uuid, created_at: DateTime<Utc>, legs: Vec<Leg>) -> Self {
+        Transaction { id, created_at, legs }
+    }
 
-    pub fn new(source: AccountId, target: AccountId, value: Decimal) -> Self {
-        Transaction {
-            source,
-            target,
-            value
-        }
+    /// Convenience constructor for the common two-leg transfer within a
+    /// single currency: debit `source` and credit `target` by `value`.
+    /// Equivalent to building a `Transaction` out of two balancing `Leg`s.
+    pub fn transfer(
+        id: Uuid,
+        created_at: DateTime<Utc>,
+        source: AccountId,
+        target: AccountId,
+        value: Decimal,
+        currency: impl Into<String>,
+    ) -> Self {
+        let currency = currency.into();
+        Transaction::new(
+            id,
+            created_at,
+            vec![
+                Leg::new(source, -value, currency.clone()),
+                Leg::new(target, value, currency),
+            ],
+        )
+    }
+
+    /// Convenience constructor for a currency-converting transfer: debits
+    /// `source_amount` of `source_currency` from `source`, and credits
+    /// `target_amount` of `target_currency` to `target`. The two amounts
+    /// are expected to already reflect the exchange rate applied by the
+    /// caller (see `core::rates`), so unlike `transfer` the legs are not
+    /// expected to net to zero as raw `Decimal`s.
+    #[allow(clippy::too_many_arguments)]
+    pub fn exchange(
+        id: Uuid,
+        created_at: DateTime<Utc>,
+        source: AccountId,
+        source_currency: impl Into<String>,
+        source_amount: Decimal,
+        target: AccountId,
+        target_currency: impl Into<String>,
+        target_amount: Decimal,
+    ) -> Self {
+        Transaction::new(
+            id,
+            created_at,
+            vec![
+                Leg::new(source, -source_amount, source_currency),
+                Leg::new(target, target_amount, target_currency),
+            ],
+        )
     }
 
-    pub fn source(&self) -> AccountId {
-        self.source
+    pub fn id(&self) -> Uuid {
+        self.id
     }
 
-    pub fn target(&self) -> AccountId {
-        self.target
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
     }
 
-    pub fn value(&self) -> Decimal {
-        self.value
+    pub fn legs(&self) -> &[Leg] {
+        &self.legs
     }
 
-}
\ No newline at end of file
+    /// True when every leg shares the same currency, i.e. there is a single
+    /// `Decimal` scale across the whole transaction and `net`/`is_balanced`
+    /// are meaningful. A correctly constructed currency-converting
+    /// transaction is in general not balanced in this sense.
+    pub fn is_single_currency(&self) -> bool {
+        match self.legs.split_first() {
+            None => true,
+            Some((first, rest)) => rest.iter().all(|leg| leg.currency() == first.currency()),
+        }
+    }
+
+    /// Sum of every leg's signed amount. Only meaningful when
+    /// `is_single_currency` is true.
+    pub fn net(&self) -> Decimal {
+        self.legs.iter().map(Leg::amount).sum()
+    }
+
+    pub fn is_balanced(&self) -> bool {
+        self.net() == Decimal::ZERO
+    }
+}